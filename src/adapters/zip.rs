@@ -2,9 +2,13 @@ use super::*;
 use crate::{adapted_iter::AdaptedFilesIter, print_bytes};
 use anyhow::*;
 use async_stream::stream;
-use async_zip::read::stream::ZipFileReader;
+use async_zip::read::stream::{ZipEntryReader, ZipFileReader};
+use globset::{Glob, GlobSet, GlobSetBuilder};
 use lazy_static::lazy_static;
 use log::*;
+use std::io::Cursor;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 
 static EXTENSIONS: &[&str] = &["zip"];
 
@@ -38,6 +42,11 @@ impl GetMetadata for ZipAdapter {
 }
 
 impl FileAdapter for ZipAdapter {
+    // `cumulative_uncompressed_size` is threaded through via `AdaptInfo` and only ever
+    // incremented/read here and in `preproc::loop_adapt`'s passthrough to recursing adapters.
+    // A mixed-format recursion chain (e.g. zip -> tar -> zip) only keeps the cumulative cap
+    // intact if every recursing adapter on the chain reads the incoming value and forwards it
+    // the same way this one does.
     fn adapt(&self, ai: AdaptInfo, _detection_reason: &FileMatcher) -> Result<AdaptedFilesIterBox> {
         let AdaptInfo {
             inp,
@@ -46,9 +55,19 @@ impl FileAdapter for ZipAdapter {
             postprocess,
             line_prefix,
             config,
+            cumulative_uncompressed_size,
             ..
         } = ai;
         let mut zip = ZipFileReader::new(inp);
+        let passwords = config.passwords.clone();
+        let max_entry_size = config.max_entry_uncompressed_size.0;
+        let max_cumulative_size = config.max_cumulative_uncompressed_size.0;
+        let max_ratio = config.max_compression_ratio;
+
+        // Shared across every entry of this archive (but not across recursion into a nested
+        // archive, which gets its own) so the cumulative cap reflects bytes actually
+        // decompressed, not the entries' (possibly untrustworthy, see below) declared sizes.
+        let cumulative = Arc::new(AtomicU64::new(cumulative_uncompressed_size));
 
         let s = stream! {
             while !zip.finished() {
@@ -57,23 +76,111 @@ impl FileAdapter for ZipAdapter {
                     /* if file.is_dir() {
                     continue;
                     }*/
+                    let file_name = file.filename().to_string();
+                    // archive_include/archive_exclude filtering happens once, generically, in
+                    // `preproc::loop_adapt` (the one point every archive entry passes through
+                    // regardless of adapter) rather than here too.
+                    let is_encrypted = file.encrypted();
+                    let declared_uncompressed_size = file.uncompressed_size();
+                    let declared_compressed_size = file.compressed_size();
+                    // A streaming zip's local header reports 0 for these sizes when the entry
+                    // uses a data descriptor (general-purpose bit 3) -- common for archives
+                    // written by streaming tools, and indistinguishable here from a genuinely
+                    // empty entry. Don't trust 0 as "small": skip the upfront header-based ratio
+                    // check for it. The cumulative cap never relies on the header at all (see
+                    // `LimitedEntryReader`/`drain_entry` below), so it still holds even when an
+                    // attacker chains many individually-small-looking such entries.
+                    let header_trustworthy = declared_uncompressed_size > 0;
+                    let mut header_exceeds_limit = false;
+                    if header_trustworthy {
+                        let ratio = if declared_compressed_size > 0 {
+                            declared_uncompressed_size as f64 / declared_compressed_size as f64
+                        } else {
+                            declared_uncompressed_size as f64
+                        };
+                        header_exceeds_limit = (max_entry_size > 0
+                            && declared_uncompressed_size > max_entry_size)
+                            || (max_ratio > 0.0 && ratio > max_ratio);
+                    }
                     debug!(
-                        "{}{}|{}: {} ({} packed)",
+                        "{}{}|{}: {} ({} packed{})",
                         line_prefix,
                         filepath_hint.display(),
-                        file.filename(),
-                        print_bytes(file.uncompressed_size() as f64),
-                        print_bytes(file.compressed_size() as f64)
+                        file_name,
+                        print_bytes(declared_uncompressed_size as f64),
+                        print_bytes(declared_compressed_size as f64),
+                        if is_encrypted { ", encrypted" } else { "" }
                     );
-                    let new_line_prefix = format!("{}{}: ", line_prefix, file.filename());
+                    let new_line_prefix = format!("{}{}: ", line_prefix, file_name);
+
+                    if header_exceeds_limit {
+                        debug!("{}skipping, decompression limit exceeded", new_line_prefix);
+                        drain_entry(&mut reader, &cumulative, max_cumulative_size).await?;
+                        let msg = format!(
+                            "{}[rga: skipped {}, decompression limit exceeded]\n",
+                            new_line_prefix, file_name
+                        )
+                        .into_bytes();
+                        yield Ok(AdaptInfo {
+                            filepath_hint: PathBuf::from(file_name),
+                            is_real_file: false,
+                            inp: Box::pin(Cursor::new(msg)),
+                            line_prefix: new_line_prefix,
+                            archive_recursion_depth: archive_recursion_depth + 1,
+                            postprocess,
+                            config: config.clone(),
+                            cumulative_uncompressed_size: cumulative.load(Ordering::Relaxed),
+                        });
+                        continue;
+                    }
+
+                    if is_encrypted {
+                        // `check_password` is the only password-related API this streaming
+                        // reader exposes: per its contract, a successful check both validates
+                        // the password and arms the entry's cipher state for the reads that
+                        // follow, so there is no separate "set password, then read" step to
+                        // thread through here -- finding a working password is sufficient.
+                        if find_working_password(&mut reader, &passwords).await?.is_none() {
+                            drain_entry(&mut reader, &cumulative, max_cumulative_size).await?;
+                            let msg = format!(
+                                "{}[rga: entry encrypted, no matching password]\n",
+                                new_line_prefix
+                            )
+                            .into_bytes();
+                            yield Ok(AdaptInfo {
+                                filepath_hint: PathBuf::from(file_name),
+                                is_real_file: false,
+                                inp: Box::pin(Cursor::new(msg)),
+                                line_prefix: new_line_prefix,
+                                archive_recursion_depth: archive_recursion_depth + 1,
+                                postprocess,
+                                config: config.clone(),
+                                cumulative_uncompressed_size: cumulative.load(Ordering::Relaxed),
+                            });
+                            continue;
+                        }
+                    }
+
+                    // A hard backstop against bytes actually decompressed, independent of
+                    // whatever the (possibly forged/untrustworthy) header claims, for both the
+                    // per-entry and the cross-entry cumulative cap.
+                    let cumulative_uncompressed_size = cumulative.load(Ordering::Relaxed);
+                    let limited = LimitedEntryReader {
+                        inner: reader,
+                        entry_read: 0,
+                        max_entry: max_entry_size,
+                        cumulative: cumulative.clone(),
+                        max_cumulative: max_cumulative_size,
+                    };
                     yield Ok(AdaptInfo {
-                        filepath_hint: PathBuf::from(file.filename()),
+                        filepath_hint: PathBuf::from(file_name),
                         is_real_file: false,
-                        inp: Box::pin(reader),
+                        inp: Box::pin(limited),
                         line_prefix: new_line_prefix,
                         archive_recursion_depth: archive_recursion_depth + 1,
                         postprocess,
                         config: config.clone(),
+                        cumulative_uncompressed_size,
                     });
                 }
             }
@@ -82,6 +189,110 @@ impl FileAdapter for ZipAdapter {
     }
 }
 
+/// Wraps an entry's reader to enforce `max_entry_uncompressed_size` and
+/// `max_cumulative_uncompressed_size` (a cap of 0 disables either) against bytes actually
+/// decompressed, rather than the entry's header-declared size. The header is not a reliable
+/// source for this: it can read as 0 for streaming zips that use a data descriptor, and is
+/// otherwise an attacker-controlled value the best-effort check in `adapt` above only applies
+/// before any bytes are read. `cumulative` is shared by every entry (read or drained) of the
+/// same archive, so the cumulative cap holds even across many individually-small entries.
+struct LimitedEntryReader<R> {
+    inner: R,
+    entry_read: u64,
+    max_entry: u64,
+    cumulative: Arc<AtomicU64>,
+    max_cumulative: u64,
+}
+
+impl<R: tokio::io::AsyncRead + Unpin> tokio::io::AsyncRead for LimitedEntryReader<R> {
+    fn poll_read(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let before = buf.filled().len();
+        let res = std::pin::Pin::new(&mut this.inner).poll_read(cx, buf);
+        if let std::task::Poll::Ready(Ok(())) = &res {
+            let read = (buf.filled().len() - before) as u64;
+            this.entry_read += read;
+            let cumulative_total = this.cumulative.fetch_add(read, Ordering::Relaxed) + read;
+            if this.max_entry > 0 && this.entry_read > this.max_entry {
+                return std::task::Poll::Ready(Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!(
+                        "zip entry exceeds max_entry_uncompressed_size ({} bytes)",
+                        this.max_entry
+                    ),
+                )));
+            }
+            if this.max_cumulative > 0 && cumulative_total > this.max_cumulative {
+                return std::task::Poll::Ready(Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!(
+                        "zip archive exceeds max_cumulative_uncompressed_size ({} bytes)",
+                        this.max_cumulative
+                    ),
+                )));
+            }
+        }
+        res
+    }
+}
+
+/// Fully discards the remaining bytes of `reader` so the underlying stream's position lands at
+/// the start of the next local file header. `ZipFileReader`'s streaming reader is backed by a
+/// single forward-only source, so `entry_reader()` for the next entry requires this entry to have
+/// been read to EOF first -- skipping it without draining would desync parsing of every entry
+/// after it in the archive. Draining still runs the bytes through `cumulative`/`max_cumulative`
+/// so a chain of skipped-but-decompressed entries can't bypass the cumulative cap either.
+async fn drain_entry(
+    reader: &mut ZipEntryReader<'_>,
+    cumulative: &Arc<AtomicU64>,
+    max_cumulative: u64,
+) -> Result<()> {
+    let mut limited = LimitedEntryReader {
+        inner: reader,
+        entry_read: 0,
+        max_entry: 0,
+        cumulative: cumulative.clone(),
+        max_cumulative,
+    };
+    tokio::io::copy(&mut limited, &mut tokio::io::sink()).await?;
+    Ok(())
+}
+
+/// Builds a `GlobSet` from `patterns`, or `None` if `patterns` is empty (no filter: callers
+/// treat a missing include set as including everything and a missing exclude set as excluding
+/// nothing). Also used by `preproc::loop_adapt` to apply `archive_include`/`archive_exclude`
+/// generically to entries from any recursing adapter, not just zip.
+pub(crate) fn build_globset(patterns: &[String]) -> Result<Option<GlobSet>> {
+    if patterns.is_empty() {
+        return Ok(None);
+    }
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(Glob::new(pattern)?);
+    }
+    Ok(Some(builder.build()?))
+}
+
+/// Tries each of `passwords` in turn against the current (encrypted) entry of `reader`,
+/// returning the first one that unlocks it, or `None` if none match. `async_zip` validates a
+/// password against the entry's header eagerly, so this does not require reading the (possibly
+/// large) entry body to find out whether a password is correct.
+async fn find_working_password(
+    reader: &mut ZipEntryReader<'_>,
+    passwords: &[String],
+) -> Result<Option<String>> {
+    for password in passwords {
+        if reader.check_password(password).await? {
+            return Ok(Some(password.clone()));
+        }
+    }
+    Ok(None)
+}
+
 /*struct ZipAdaptIter {
     inp: AdaptInfo,
 }
@@ -143,6 +354,127 @@ mod test {
         // Dropping the `ZipWriter` will have the same effect, but may silently fail
         Ok(zip.finish()?.into_inner())
     }
+    // archive_include/archive_exclude filtering now happens once, generically, in
+    // `preproc::loop_adapt` rather than here; see the test of that behavior in `preproc.rs`.
+
+    #[test]
+    fn encrypted_entry_without_matching_password_is_skipped_without_desyncing_later_entries(
+    ) -> Result<()> {
+        use std::io::Write;
+        let mut zip = ::zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        let plain_options = ::zip::write::FileOptions::default()
+            .compression_method(::zip::CompressionMethod::Stored);
+        let encrypted_options = ::zip::write::FileOptions::default()
+            .compression_method(::zip::CompressionMethod::Stored)
+            .with_aes_encryption(::zip::AesMode::Aes256, "correct horse battery staple");
+        zip.start_file("a.txt", plain_options)?;
+        zip.write_all(b"file a")?;
+        zip.start_file("secret.txt", encrypted_options)?;
+        zip.write_all(b"file secret")?;
+        zip.start_file("c.txt", plain_options)?;
+        zip.write_all(b"file c")?;
+        let zipfile = zip.finish()?.into_inner();
+
+        let adapter: Box<dyn FileAdapter> = Box::new(ZipAdapter::new());
+        let (mut a, d) = simple_adapt_info(
+            &PathBuf::from("test.zip"),
+            Box::new(std::io::Cursor::new(zipfile)),
+        );
+        a.config.passwords = vec!["wrong password".to_string()];
+        let buf = adapted_to_vec(adapter.adapt(a, &d)?)?;
+        let out = String::from_utf8(buf)?;
+
+        assert!(out.contains("PREFIX:a.txt: file a"));
+        assert!(out.contains("no matching password"));
+        assert!(out.contains("PREFIX:c.txt: file c"));
+        Ok(())
+    }
+
+    #[test]
+    fn oversized_entry_is_skipped_without_desyncing_later_entries() -> Result<()> {
+        use std::io::Write;
+        let mut zip = ::zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        let options = ::zip::write::FileOptions::default()
+            .compression_method(::zip::CompressionMethod::Stored);
+        zip.start_file("big.txt", options)?;
+        zip.write_all(&vec![b'x'; 1000])?;
+        zip.start_file("small.txt", options)?;
+        zip.write_all(b"ok")?;
+        let zipfile = zip.finish()?.into_inner();
+
+        let adapter: Box<dyn FileAdapter> = Box::new(ZipAdapter::new());
+        let (mut a, d) = simple_adapt_info(
+            &PathBuf::from("test.zip"),
+            Box::new(std::io::Cursor::new(zipfile)),
+        );
+        a.config.max_entry_uncompressed_size.0 = 100;
+        let buf = adapted_to_vec(adapter.adapt(a, &d)?)?;
+        let out = String::from_utf8(buf)?;
+
+        assert!(out.contains("decompression limit exceeded"));
+        assert!(out.contains("PREFIX:small.txt: ok"));
+        Ok(())
+    }
+
+    #[test]
+    fn ratio_limit_skips_entry_without_desyncing_later_entries() -> Result<()> {
+        use std::io::Write;
+        let mut zip = ::zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        let options = ::zip::write::FileOptions::default()
+            .compression_method(::zip::CompressionMethod::Stored);
+        zip.start_file("a.txt", options)?;
+        zip.write_all(b"file a")?;
+        zip.start_file("b.txt", options)?;
+        zip.write_all(b"file b")?;
+        let zipfile = zip.finish()?.into_inner();
+
+        let adapter: Box<dyn FileAdapter> = Box::new(ZipAdapter::new());
+        let (mut a, d) = simple_adapt_info(
+            &PathBuf::from("test.zip"),
+            Box::new(std::io::Cursor::new(zipfile)),
+        );
+        // `Stored` entries always have a 1:1 compressed/uncompressed ratio, so any threshold
+        // below 1.0 forces every entry in this archive over the limit.
+        a.config.max_compression_ratio = 0.5;
+        let buf = adapted_to_vec(adapter.adapt(a, &d)?)?;
+        let out = String::from_utf8(buf)?;
+
+        assert!(out.contains("PREFIX:a.txt: [rga: skipped a.txt, decompression limit exceeded]"));
+        assert!(out.contains("PREFIX:b.txt: [rga: skipped b.txt, decompression limit exceeded]"));
+        Ok(())
+    }
+
+    #[test]
+    fn cumulative_limit_is_enforced_against_bytes_actually_read() -> Result<()> {
+        use std::io::Write;
+        let mut zip = ::zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        let options = ::zip::write::FileOptions::default()
+            .compression_method(::zip::CompressionMethod::Stored);
+        zip.start_file("a.txt", options)?;
+        zip.write_all(&vec![b'a'; 50])?;
+        zip.start_file("b.txt", options)?;
+        zip.write_all(&vec![b'b'; 50])?;
+        let zipfile = zip.finish()?.into_inner();
+
+        let adapter: Box<dyn FileAdapter> = Box::new(ZipAdapter::new());
+        let (mut a, d) = simple_adapt_info(
+            &PathBuf::from("test.zip"),
+            Box::new(std::io::Cursor::new(zipfile)),
+        );
+        // Each entry (50 bytes) is individually within bounds, but the two together (100 bytes)
+        // exceed the 75-byte cumulative cap, which is enforced against the bytes
+        // `LimitedEntryReader` actually streams, so reading the second entry fails.
+        a.config.max_cumulative_uncompressed_size.0 = 75;
+        let result = adapted_to_vec(adapter.adapt(a, &d)?);
+
+        assert!(
+            result.is_err(),
+            "reading past the cumulative cap across multiple entries should error, got {:?}",
+            result
+        );
+        Ok(())
+    }
+
     #[test]
     fn recurse() -> Result<()> {
         let zipfile = create_zip("outer.txt", "outer text file", true)?;
@@ -11,6 +11,7 @@ use crate::{
 use anyhow::*;
 use async_compression::tokio::bufread::ZstdDecoder;
 use async_stream::stream;
+use futures::stream::{self, StreamExt};
 use log::*;
 use path_clean::PathClean;
 use postproc::PostprocPrefix;
@@ -19,6 +20,7 @@ use std::path::Path;
 use std::sync::Arc;
 use tokio::io::AsyncBufRead;
 use tokio::io::AsyncBufReadExt;
+use tokio::io::AsyncReadExt;
 use tokio::io::BufReader;
 
 type ActiveAdapters = Vec<Arc<dyn FileAdapter>>;
@@ -120,30 +122,93 @@ pub async fn rga_preproc(ai: AdaptInfo) -> Result<ReadBox> {
         .with_context(|| format!("run_adapter({})", &path_hint_copy.to_string_lossy()))
 }
 
-fn compute_cache_key(
+/// Identifies a file for cache lookup purposes. `PathAndMtime` is cheap but invalidated by moving
+/// or copying the file; `ContentHash` survives that at the cost of reading the file once. Above
+/// `HYBRID_HASH_THRESHOLD` the `ContentHash` key also includes the mtime, so there only a move or
+/// copy that keeps the mtime maps to the same key.
+#[derive(serde::Serialize, Debug)]
+enum CacheFileIdentity {
+    PathAndMtime(std::path::PathBuf, std::time::SystemTime),
+    // Per the hybrid scheme, mtime is included alongside the size + head/tail hash: two large
+    // files can plausibly share size and head/tail bytes (e.g. disk images, container formats
+    // with fixed headers/footers), and without mtime as a discriminator they'd collide on the
+    // same cache key and silently serve each other's stale cached adapter output.
+    ContentHash {
+        len: u64,
+        hash: [u8; 32],
+        mtime: Option<std::time::SystemTime>,
+    },
+}
+
+/// Above this size, `hash_file_for_cache_key` only hashes the first and last `HYBRID_HASH_CHUNK`
+/// bytes of the file (plus its length) instead of the whole thing, to bound hashing cost for very
+/// large inputs. The cache key additionally folds in the file's mtime for files in this hybrid
+/// regime, since size + head/tail bytes alone aren't a strong enough discriminator at this scale.
+const HYBRID_HASH_THRESHOLD: u64 = 64 * 1024 * 1024;
+const HYBRID_HASH_CHUNK: u64 = 1 << 16;
+
+fn hash_file_for_cache_key(path: &Path, len: u64) -> Result<[u8; 32]> {
+    use std::io::{Read, Seek, SeekFrom};
+    let mut file = std::fs::File::open(path)
+        .with_context(|| format!("opening {} for content hashing", path.to_string_lossy()))?;
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(&len.to_le_bytes());
+    if len <= HYBRID_HASH_THRESHOLD {
+        std::io::copy(&mut file, &mut hasher)?;
+    } else {
+        let mut buf = vec![0u8; HYBRID_HASH_CHUNK as usize];
+        file.read_exact(&mut buf)?;
+        hasher.update(&buf);
+        file.seek(SeekFrom::End(-(HYBRID_HASH_CHUNK as i64)))?;
+        file.read_exact(&mut buf)?;
+        hasher.update(&buf);
+    }
+    Ok(*hasher.finalize().as_bytes())
+}
+
+async fn compute_cache_key(
     filepath_hint: &Path,
     adapter: &dyn FileAdapter,
     active_adapters: ActiveAdapters,
+    config: &RgaConfig,
 ) -> Result<Vec<u8>> {
-    let clean_path = filepath_hint.to_owned().clean();
     let meta = std::fs::metadata(filepath_hint)
         .with_context(|| format!("reading metadata for {}", filepath_hint.to_string_lossy()))?;
-    let modified = meta.modified().expect("weird OS that can't into mtime");
+    let identity = if config.cache.content_hash {
+        let mtime = meta.modified().ok();
+        let hybrid = mtime.is_some() && meta.len() > HYBRID_HASH_THRESHOLD;
+        let len = meta.len();
+        let path = filepath_hint.to_owned();
+        // Hashing reads the whole file (or at least two chunks of it) off disk synchronously;
+        // run it on a blocking-pool thread so it doesn't stall the tokio worker thread running
+        // this task while the I/O happens.
+        let hash = tokio::task::spawn_blocking(move || hash_file_for_cache_key(&path, len))
+            .await
+            .context("content hashing task panicked")??;
+        CacheFileIdentity::ContentHash {
+            len,
+            hash,
+            mtime: if hybrid { mtime } else { None },
+        }
+    } else {
+        let clean_path = filepath_hint.to_owned().clean();
+        let modified = meta.modified().expect("weird OS that can't into mtime");
+        CacheFileIdentity::PathAndMtime(clean_path, modified)
+    };
 
     if adapter.metadata().recurses {
         let active_adapters_cache_key = active_adapters
             .iter()
             .map(|a| (a.metadata().name.clone(), a.metadata().version))
             .collect::<Vec<_>>();
-        let key = (active_adapters_cache_key, clean_path, modified);
+        let key = (active_adapters_cache_key, identity);
         debug!("Cache key (with recursion): {:?}", key);
         bincode::serialize(&key).context("could not serialize path")
     } else {
         let key = (
             adapter.metadata().name.clone(),
             adapter.metadata().version,
-            clean_path,
-            modified,
+            identity,
         );
         debug!("Cache key (no recursion): {:?}", key);
         bincode::serialize(&key).context("could not serialize path")
@@ -177,8 +242,13 @@ async fn adapt_caching(
     };
 
     let mut cache = cache.context("No cache?")?;
-    let cache_key: Vec<u8> =
-        compute_cache_key(&ai.filepath_hint, adapter.as_ref(), active_adapters)?;
+    let cache_key: Vec<u8> = compute_cache_key(
+        &ai.filepath_hint,
+        adapter.as_ref(),
+        active_adapters,
+        &ai.config,
+    )
+    .await?;
     // let dbg_ctx = format!("adapter {}", &adapter.metadata().name);
     let cached = cache.get(&db_name, &cache_key)?;
     match cached {
@@ -209,12 +279,90 @@ async fn adapt_caching(
     }
 }
 
+/// How many entries of a single archive to run adapter-selection and recursion for at once, see
+/// `RgaConfig::max_concurrent_adapts`. `0` means "use the number of available cores".
+fn adapt_concurrency(config: &RgaConfig) -> usize {
+    let configured = config.max_concurrent_adapts.0;
+    if configured > 0 {
+        configured as usize
+    } else {
+        std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1)
+    }
+}
+
+/// Runs a single entry's adapter-selection/recursion to completion, collecting every item it
+/// yields into a `Vec` so it can be run inside a `buffered` pool and flushed in order once ready.
+async fn run_choice(choice: Ret) -> Result<Vec<Result<AdaptInfo>>> {
+    match choice {
+        Ret::Recurse(ai, adapter, detection_reason, _active_adapters) => {
+            if ai.archive_recursion_depth >= ai.config.max_archive_recursion.0 {
+                let s = format!(
+                    "{}[rga: max archive recursion reached ({})]",
+                    ai.line_prefix, ai.archive_recursion_depth
+                )
+                .into_bytes();
+                return Ok(vec![Ok(AdaptInfo {
+                    inp: Box::pin(Cursor::new(s)),
+                    ..ai
+                })]);
+            }
+            debug!(
+                "Chose adapter '{}' because of matcher {:?}",
+                &adapter.metadata().name,
+                &detection_reason
+            );
+            eprintln!(
+                "{} adapter: {}",
+                ai.filepath_hint.to_string_lossy(),
+                &adapter.metadata().name
+            );
+            Ok(loop_adapt(adapter.as_ref(), detection_reason, ai)?
+                .collect::<Vec<_>>()
+                .await)
+        }
+        Ret::Passthrough(ai) => {
+            debug!(
+                "no adapter for {}, ending recursion",
+                ai.filepath_hint.to_string_lossy()
+            );
+            Ok(vec![Ok(ai)])
+        }
+    }
+}
+
+/// Reads at most `cap + 1` bytes from `inp`. If the entry turns out to fit within `cap`, returns
+/// the buffered bytes and `None`. Otherwise returns the bytes read so far and `Some(_)` wrapping
+/// whatever of the stream is left unread, so the caller can keep streaming it directly instead of
+/// buffering the rest, bounding how much of any one entry is ever held in memory at once.
+async fn buffer_entry_bounded(mut inp: ReadBox, cap: u64) -> Result<(Vec<u8>, Option<ReadBox>)> {
+    let mut buf = Vec::new();
+    {
+        let mut limited = (&mut inp).take(cap + 1);
+        limited.read_to_end(&mut buf).await?;
+    }
+    if (buf.len() as u64) <= cap {
+        Ok((buf, None))
+    } else {
+        Ok((buf, Some(inp)))
+    }
+}
+
 pub fn loop_adapt(
     adapter: &dyn FileAdapter,
     detection_reason: FileMatcher,
     ai: AdaptInfo,
 ) -> anyhow::Result<AdaptedFilesIterBox> {
     let fph = ai.filepath_hint.clone();
+    let concurrency = adapt_concurrency(&ai.config);
+    let max_buffered_entry_bytes = ai.config.max_concurrent_adapt_buffer_size.0;
+    // Built here, generically, so `archive_include`/`archive_exclude` apply to entries produced
+    // by *any* recursing adapter (zip, tar, ...), not just the ones that happen to filter their
+    // own entries -- this is the one point every archive entry passes through regardless of
+    // adapter, and it applies again at every recursion depth since loop_adapt calls itself.
+    let include = zip::build_globset(&ai.config.archive_include)?;
+    let exclude = zip::build_globset(&ai.config.archive_exclude)?;
     let inp = adapter.adapt(ai, &detection_reason).with_context(|| {
         format!(
             "adapting {} via {} failed",
@@ -223,36 +371,186 @@ pub fn loop_adapt(
         )
     })?;
     let s = stream! {
-        for await file in inp {
-            match buf_choose_adapter(file?).await? {
-                Ret::Recurse(ai, adapter, detection_reason, _active_adapters) => {
-                    if ai.archive_recursion_depth >= ai.config.max_archive_recursion.0 {
-                        let s = format!("{}[rga: max archive recursion reached ({})]", ai.line_prefix, ai.archive_recursion_depth).into_bytes();
-                        yield Ok(AdaptInfo {
-                            inp: Box::pin(Cursor::new(s)),
-                            ..ai
-                        });
-                        continue;
-                    }
+        // Archive entries have to be read off the underlying stream in order regardless of
+        // concurrency, so we pull and buffer only up to `concurrency` of them at a time (bounded
+        // additionally by `max_buffered_entry_bytes` per entry, so one huge entry can't blow the
+        // memory budget) and fan the CPU-heavy adapter-selection/recursion work for that window
+        // out across a bounded pool. `buffered` (as opposed to `buffer_unordered`) still yields
+        // results in original archive order even though the work behind them completes out of
+        // order. An entry that doesn't fit the buffer cap is run on its own, streamed directly
+        // with no concurrency, instead of being buffered in full.
+        let mut inp = inp;
+        loop {
+            let mut batch = Vec::new();
+            let mut oversized = None;
+            while batch.len() < concurrency {
+                let file = match inp.next().await {
+                    Some(file) => file?,
+                    None => break,
+                };
+                let name = file.filepath_hint.to_string_lossy().to_string();
+                let included = include.as_ref().map_or(true, |g| g.is_match(&name));
+                let excluded = exclude.as_ref().map_or(false, |g| g.is_match(&name));
+                if !included || excluded {
                     debug!(
-                        "Chose adapter '{}' because of matcher {:?}",
-                        &adapter.metadata().name, &detection_reason
-                    );
-                    eprintln!(
-                        "{} adapter: {}",
-                        ai.filepath_hint.to_string_lossy(),
-                        &adapter.metadata().name
+                        "{}: skipping, excluded by archive_include/archive_exclude",
+                        name
                     );
-                    for await ifile in loop_adapt(adapter.as_ref(), detection_reason, ai)? {
-                        yield ifile;
+                    // Drain generically: whatever adapter produced this entry, its reader must
+                    // not be dropped unread if the adapter's own internal stream relies on the
+                    // consumer having read this entry to completion before advancing.
+                    tokio::io::copy(&mut file.inp, &mut tokio::io::sink()).await?;
+                    continue;
+                }
+                let (buf, rest) = buffer_entry_bounded(file.inp, max_buffered_entry_bytes).await?;
+                match rest {
+                    None => batch.push(AdaptInfo {
+                        inp: Box::pin(Cursor::new(buf)),
+                        ..file
+                    }),
+                    Some(rest) => {
+                        oversized = Some(AdaptInfo {
+                            inp: Box::pin(Cursor::new(buf).chain(rest)),
+                            ..file
+                        });
+                        break;
                     }
                 }
-                Ret::Passthrough(ai) => {
-                    debug!("no adapter for {}, ending recursion", ai.filepath_hint.to_string_lossy());
-                    yield Ok(ai);
+            }
+
+            if batch.is_empty() && oversized.is_none() {
+                break;
+            }
+
+            let tasks = stream::iter(batch.into_iter().map(|file| async move {
+                run_choice(buf_choose_adapter(file).await?).await
+            }))
+            .buffered(concurrency);
+            for await items in tasks {
+                for item in items? {
+                    yield item;
+                }
+            }
+
+            if let Some(file) = oversized {
+                let choice = buf_choose_adapter(file).await?;
+                for item in run_choice(choice).await? {
+                    yield item;
                 }
             }
         }
     };
     Ok(Box::pin(s))
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn hybrid_identity_changes_with_mtime() {
+        let a = CacheFileIdentity::ContentHash {
+            len: HYBRID_HASH_THRESHOLD + 1,
+            hash: [0u8; 32],
+            mtime: Some(std::time::SystemTime::UNIX_EPOCH),
+        };
+        let b = CacheFileIdentity::ContentHash {
+            len: HYBRID_HASH_THRESHOLD + 1,
+            hash: [0u8; 32],
+            mtime: Some(std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1)),
+        };
+        let ser_a = bincode::serialize(&a).unwrap();
+        let ser_b = bincode::serialize(&b).unwrap();
+        assert_ne!(
+            ser_a, ser_b,
+            "two large files with identical size/hash but different mtimes must not collide on the hybrid cache key"
+        );
+    }
+
+    #[test]
+    fn content_hash_cache_key_survives_rename_and_changes_with_content() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!(
+            "rga-compute-cache-key-test-{}-{:?}",
+            std::process::id(),
+            std::thread::current().id()
+        ));
+        std::fs::create_dir_all(&dir)?;
+
+        let original = dir.join("original.txt");
+        let renamed = dir.join("renamed.txt");
+        let changed = dir.join("changed.txt");
+        std::fs::write(&original, b"identical content")?;
+        std::fs::write(&renamed, b"identical content")?;
+        std::fs::write(&changed, b"different content")?;
+
+        let mut config = RgaConfig::default();
+        config.cache.content_hash = true;
+        let adapter: Box<dyn FileAdapter> = Box::new(zip::ZipAdapter::new());
+        let active_adapters: ActiveAdapters = Vec::new();
+
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let key_original = rt.block_on(compute_cache_key(
+            &original,
+            adapter.as_ref(),
+            active_adapters.clone(),
+            &config,
+        ))?;
+        let key_renamed = rt.block_on(compute_cache_key(
+            &renamed,
+            adapter.as_ref(),
+            active_adapters.clone(),
+            &config,
+        ))?;
+        let key_changed = rt.block_on(compute_cache_key(
+            &changed,
+            adapter.as_ref(),
+            active_adapters,
+            &config,
+        ))?;
+
+        std::fs::remove_dir_all(&dir).ok();
+
+        assert_eq!(
+            key_original, key_renamed,
+            "a renamed/copied file with identical content must hit the same content-hash cache key"
+        );
+        assert_ne!(
+            key_original, key_changed,
+            "a file with different content must not share a cache key"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn loop_adapt_applies_archive_exclude_generically() -> Result<()> {
+        use std::io::Write;
+        let mut zip = ::zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        let options = ::zip::write::FileOptions::default()
+            .compression_method(::zip::CompressionMethod::Stored);
+        zip.start_file("a.txt", options)?;
+        zip.write_all(b"file a")?;
+        zip.start_file("b.skip", options)?;
+        zip.write_all(b"file b, should be skipped")?;
+        zip.start_file("c.txt", options)?;
+        zip.write_all(b"file c")?;
+        let zipfile = zip.finish()?.into_inner();
+
+        let adapter: Box<dyn FileAdapter> = Box::new(zip::ZipAdapter::new());
+        let (mut a, d) = simple_adapt_info(
+            &PathBuf::from("test.zip"),
+            Box::new(std::io::Cursor::new(zipfile)),
+        );
+        a.config.archive_exclude = vec!["*.skip".to_string()];
+        let buf = adapted_to_vec(loop_adapt(adapter.as_ref(), d, a)?)?;
+
+        assert_eq!(
+            String::from_utf8(buf)?,
+            "PREFIX:a.txt: file a\nPREFIX:c.txt: file c\n",
+        );
+        Ok(())
+    }
+}